@@ -1,7 +1,10 @@
+use rand::Rng;
 use reqwest::Client;
 use serde::Deserialize;
 use std::env;
+use std::future::Future;
 use std::sync::Arc;
+use std::time::Duration;
 use tracing::{error, info, warn};
 
 const USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/91.0.4472.124 Safari/537.36";
@@ -40,64 +43,274 @@ struct BhdExchangeRate {
     selling_rate: f64,
 }
 
+// --- Retry policy ---
+
+// A single fetch attempt either succeeds, fails in a way worth retrying
+// (network blip, 429/5xx, or a WAF challenge page), or fails for good
+// (the content came back but didn't parse) — in which case retrying would
+// just burn requests against a page that isn't going to change.
+pub(crate) enum FetchOutcome {
+    Success(FetchedRate),
+    Retryable,
+    Fatal,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Reads `FETCH_RETRY_MAX_ATTEMPTS`/`FETCH_RETRY_BASE_DELAY_MS`, preferring
+    /// a per-bank override (`FETCH_RETRY_MAX_ATTEMPTS_BANRESERVAS`, etc.) so a
+    /// single flaky bank can be tuned without affecting the others.
+    fn from_env(bank_env_suffix: &str) -> Self {
+        let max_attempts =
+            env_var_parsed::<u32>(&format!("FETCH_RETRY_MAX_ATTEMPTS_{bank_env_suffix}"))
+                .or_else(|| env_var_parsed("FETCH_RETRY_MAX_ATTEMPTS"))
+                .filter(|&n| n > 0)
+                .unwrap_or(5);
+        let base_delay_ms =
+            env_var_parsed::<u64>(&format!("FETCH_RETRY_BASE_DELAY_MS_{bank_env_suffix}"))
+                .or_else(|| env_var_parsed("FETCH_RETRY_BASE_DELAY_MS"))
+                .unwrap_or(300);
+
+        Self {
+            max_attempts,
+            base_delay: Duration::from_millis(base_delay_ms),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+
+    /// Exponential backoff (factor 2.0) from `base_delay`, capped at
+    /// `max_delay`, with +/-50% jitter so concurrent cycles don't retry
+    /// in lockstep against the same bank.
+    fn delay_for_attempt(&self, attempt: u32) -> Duration {
+        let exp_ms = self.base_delay.as_millis() as f64 * 2f64.powi(attempt as i32);
+        let max_ms = self.max_delay.as_millis() as f64;
+        let jitter = rand::thread_rng().gen_range(0.5..=1.5);
+        let jittered_ms = (exp_ms * jitter).min(max_ms);
+        Duration::from_millis(jittered_ms as u64)
+    }
+}
+
+/// Runs `attempt` up to `policy.max_attempts` times, backing off between
+/// retryable failures. Returns `None` once attempts are exhausted or a
+/// fatal (non-retryable) outcome is hit.
+async fn retry_fetch<F, Fut>(bank: &str, policy: RetryPolicy, attempt: F) -> Option<FetchedRate>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = FetchOutcome>,
+{
+    for n in 0..policy.max_attempts {
+        match attempt().await {
+            FetchOutcome::Success(rate) => return Some(rate),
+            FetchOutcome::Fatal => return None,
+            FetchOutcome::Retryable => {
+                if n + 1 == policy.max_attempts {
+                    error!("{bank}: giving up after {} attempts", policy.max_attempts);
+                    return None;
+                }
+                let delay = policy.delay_for_attempt(n);
+                warn!(
+                    "{bank}: retryable failure on attempt {}/{}, retrying in {:?}",
+                    n + 1,
+                    policy.max_attempts,
+                    delay
+                );
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+    None
+}
+
+/// Heuristic for "this isn't the real page, it's a WAF challenge" —
+/// worth retrying rather than treating as a permanent parse failure.
+fn looks_like_waf_challenge(body: &str) -> bool {
+    body.contains("Incapsula") || body.contains("_Incapsula_Resource") || body.len() < 200
+}
+
+fn env_var_parsed<T: std::str::FromStr>(key: &str) -> Option<T> {
+    env::var(key).ok().and_then(|v| v.parse().ok())
+}
+
+// --- Fetcher registry ---
+
+/// Implemented once per bank. `fetch` owns that bank's retry/backoff
+/// policy and whatever request shape it needs (regex scrape, JSON API,
+/// multi-step WAF dance) — `update_all_rates` only ever sees this trait.
+#[async_trait::async_trait]
+pub trait BankFetcher: Send + Sync {
+    fn bank_class(&self) -> &'static str;
+
+    async fn fetch(&self, client: &Client) -> Option<FetchedRate>;
+}
+
+/// Builds the set of fetchers driven by `update_all_rates`. Adding a bank
+/// is implementing `BankFetcher` and appending it here — nothing else in
+/// the crate needs to change.
+///
+/// Returned as `Arc<dyn BankFetcher>` rather than `Box<dyn BankFetcher>` so
+/// callers can clone an owned, `'static` handle into each fetch future
+/// instead of borrowing from the registry — required once those futures
+/// are driven from inside a spawned background task.
+pub fn registry() -> Vec<Arc<dyn BankFetcher>> {
+    vec![
+        Arc::new(BanreservasFetcher::new()),
+        Arc::new(BhdFetcher::new()),
+        Arc::new(PopularFetcher::new()),
+    ]
+}
+
 // --- Fetchers ---
 
-pub async fn fetch_banreservas(client: &Client) -> Option<FetchedRate> {
-    let response = client
+pub struct BanreservasFetcher {
+    retry: RetryPolicy,
+}
+
+impl BanreservasFetcher {
+    pub fn new() -> Self {
+        Self {
+            retry: RetryPolicy::from_env("BANRESERVAS"),
+        }
+    }
+}
+
+impl Default for BanreservasFetcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait::async_trait]
+impl BankFetcher for BanreservasFetcher {
+    fn bank_class(&self) -> &'static str {
+        "banreservas"
+    }
+
+    async fn fetch(&self, client: &Client) -> Option<FetchedRate> {
+        retry_fetch("Banreservas", self.retry, || banreservas_attempt(client)).await
+    }
+}
+
+async fn banreservas_attempt(client: &Client) -> FetchOutcome {
+    let response = match client
         .get("https://www.banreservas.com/calculadoras/")
         .header("User-Agent", USER_AGENT)
         .send()
         .await
-        .map_err(|e| error!("Banreservas request failed: {e}"))
-        .ok()?;
+    {
+        Ok(response) => response,
+        Err(e) => {
+            error!("Banreservas request failed: {e}");
+            return FetchOutcome::Retryable;
+        }
+    };
 
-    let html = response
-        .text()
-        .await
-        .map_err(|e| error!("Banreservas body read failed: {e}"))
-        .ok()?;
+    if response.status() == 429 || response.status().is_server_error() {
+        error!("Banreservas returned HTTP {}", response.status());
+        return FetchOutcome::Retryable;
+    }
+
+    let html = match response.text().await {
+        Ok(html) => html,
+        Err(e) => {
+            error!("Banreservas body read failed: {e}");
+            return FetchOutcome::Retryable;
+        }
+    };
 
-    let re = regex::Regex::new(r"(?s)Compra\s*(\d+\.\d+).*?Venta\s*(\d+\.\d+)").ok()?;
-    let caps = re.captures(&html).or_else(|| {
+    parse_banreservas_html(&html)
+}
+
+/// Pulls buy/sell rates out of the Banreservas calculator page HTML. Split
+/// out from `banreservas_attempt` so a saved fixture can be run through the
+/// same regex offline (see the `parse-file` CLI subcommand).
+pub(crate) fn parse_banreservas_html(html: &str) -> FetchOutcome {
+    let re = regex::Regex::new(r"(?s)Compra\s*(\d+\.\d+).*?Venta\s*(\d+\.\d+)").unwrap();
+    let Some(caps) = re.captures(html) else {
+        if looks_like_waf_challenge(html) {
+            warn!("Banreservas: page looks like a WAF challenge, will retry");
+            return FetchOutcome::Retryable;
+        }
         error!("Banreservas: could not parse rates from HTML");
-        None
-    })?;
+        return FetchOutcome::Fatal;
+    };
+
+    let (Ok(dollar_buy_rate), Ok(dollar_sell_rate)) = (caps[1].parse(), caps[2].parse()) else {
+        error!("Banreservas: matched rates were not valid numbers");
+        return FetchOutcome::Fatal;
+    };
 
-    Some(FetchedRate {
+    FetchOutcome::Success(FetchedRate {
         bank_name: "Banreservas".into(),
         bank_class: "banreservas".into(),
-        dollar_buy_rate: caps[1].parse().ok()?,
-        dollar_sell_rate: caps[2].parse().ok()?,
+        dollar_buy_rate,
+        dollar_sell_rate,
     })
 }
 
-pub async fn fetch_bhd(client: &Client) -> Option<FetchedRate> {
-    let response = client
+async fn bhd_attempt(client: &Client) -> FetchOutcome {
+    let response = match client
         .get("https://backend.bhd.com.do/api/modal-cambio-rate?populate=deep")
         .header("User-Agent", USER_AGENT)
         .send()
         .await
-        .map_err(|e| error!("BHD request failed: {e}"))
-        .ok()?;
+    {
+        Ok(response) => response,
+        Err(e) => {
+            error!("BHD request failed: {e}");
+            return FetchOutcome::Retryable;
+        }
+    };
 
-    let data: BhdApiResponse = response
-        .json()
-        .await
-        .map_err(|e| error!("BHD JSON parse failed: {e}"))
-        .ok()?;
+    if response.status() == 429 || response.status().is_server_error() {
+        error!("BHD returned HTTP {}", response.status());
+        return FetchOutcome::Retryable;
+    }
+
+    let body = match response.text().await {
+        Ok(body) => body,
+        Err(e) => {
+            error!("BHD body read failed: {e}");
+            return FetchOutcome::Retryable;
+        }
+    };
+
+    parse_bhd_json(&body)
+}
+
+/// Decodes the BHD exchange-rate-modal response. Split out from
+/// `bhd_attempt` so a saved fixture can be run through the same decoder
+/// offline (see the `parse-file` CLI subcommand).
+pub(crate) fn parse_bhd_json(body: &str) -> FetchOutcome {
+    let data: BhdApiResponse = match serde_json::from_str(body) {
+        Ok(data) => data,
+        Err(e) => {
+            if looks_like_waf_challenge(body) {
+                warn!("BHD: response looks like a WAF challenge, will retry");
+                return FetchOutcome::Retryable;
+            }
+            error!("BHD JSON parse failed: {e}");
+            return FetchOutcome::Fatal;
+        }
+    };
 
-    let usd = data
+    let Some(usd) = data
         .data
         .attributes
         .exchange_rates
         .iter()
         .find(|r| r.currency == "USD")
-        .or_else(|| {
-            error!("BHD: USD rate not found");
-            None
-        })?;
+    else {
+        error!("BHD: USD rate not found");
+        return FetchOutcome::Fatal;
+    };
 
-    Some(FetchedRate {
+    FetchOutcome::Success(FetchedRate {
         bank_name: "BHD".into(),
         bank_class: "bhd".into(),
         dollar_buy_rate: usd.buying_rate,
@@ -105,6 +318,35 @@ pub async fn fetch_bhd(client: &Client) -> Option<FetchedRate> {
     })
 }
 
+pub struct BhdFetcher {
+    retry: RetryPolicy,
+}
+
+impl BhdFetcher {
+    pub fn new() -> Self {
+        Self {
+            retry: RetryPolicy::from_env("BHD"),
+        }
+    }
+}
+
+impl Default for BhdFetcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait::async_trait]
+impl BankFetcher for BhdFetcher {
+    fn bank_class(&self) -> &'static str {
+        "bhd"
+    }
+
+    async fn fetch(&self, client: &Client) -> Option<FetchedRate> {
+        retry_fetch("BHD", self.retry, || bhd_attempt(client)).await
+    }
+}
+
 fn browser_headers(rb: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
     rb.header("User-Agent", "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/145.0.0.0 Safari/537.36")
         .header("Accept", "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,*/*;q=0.8")
@@ -119,117 +361,329 @@ fn browser_headers(rb: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
         .header("Upgrade-Insecure-Requests", "1")
 }
 
-pub async fn fetch_popular(_client: &Client) -> Option<FetchedRate> {
-    // Banco Popular exposes rates via SharePoint REST API (XML/OData).
-    // Site is behind Incapsula WAF — we emulate a browser session:
-    // 1. Visit the homepage to collect Incapsula cookies
-    // 2. Fetch the Incapsula challenge script to get session cookies
-    // 3. Use accumulated cookies to call the API
+const POPULAR_API_URL: &str =
+    "https://popularenlinea.com/_api/web/lists/getbytitle('Rates')/items?$filter=ItemID%20eq%20%271%27";
+
+/// Outcome of a bare API call against Popular's SharePoint endpoint: either
+/// it answered (with a rate or a genuine parse failure), or the session
+/// cookies are missing/stale and the Incapsula challenge needs solving.
+enum ApiAttempt {
+    Answered(FetchOutcome),
+    NeedsChallenge,
+}
+
+async fn popular_attempt(client: &Client, session: &Client) -> FetchOutcome {
+    // Banco Popular exposes rates via SharePoint REST API (XML/OData),
+    // behind an Incapsula WAF. `session` carries a cookie jar that survives
+    // across fetch cycles, so most cycles skip the challenge dance
+    // entirely and go straight to the API with cookies from last time.
 
-    // If POPULAR_PROXY_URL is set, use it directly (skip browser emulation)
+    // If POPULAR_PROXY_URL is set, use it directly (skip browser emulation and cookies)
     if let Ok(proxy_url) = env::var("POPULAR_PROXY_URL") {
         info!("Popular: using proxy URL");
-        let response = _client
+        let response = match client
             .get(&proxy_url)
             .header("User-Agent", USER_AGENT)
             .header("Accept", "application/xml")
             .send()
             .await
-            .map_err(|e| error!("Popular proxy request failed: {e}"))
-            .ok()?;
+        {
+            Ok(response) => response,
+            Err(e) => {
+                error!("Popular proxy request failed: {e}");
+                return FetchOutcome::Retryable;
+            }
+        };
         if response.status().is_success() {
-            let xml = response.text().await.ok()?;
-            return parse_popular_xml(&xml);
+            return match response.text().await {
+                Ok(xml) => parse_popular_xml(&xml),
+                Err(e) => {
+                    error!("Popular proxy body read failed: {e}");
+                    FetchOutcome::Retryable
+                }
+            };
         }
         warn!("Popular proxy returned non-200, falling back to direct");
     }
 
-    // Build a client with a cookie jar to accumulate Incapsula cookies
-    let jar = Arc::new(reqwest::cookie::Jar::default());
-    let popular_client = reqwest::Client::builder()
-        .cookie_provider(jar.clone())
-        .redirect(reqwest::redirect::Policy::limited(10))
-        .build()
-        .ok()?;
+    info!("Popular: trying rates API with existing session cookies");
+    if let ApiAttempt::Answered(outcome) = fetch_popular_api(session).await {
+        return outcome;
+    }
 
-    // Step 1: Hit the homepage to trigger Incapsula challenge and collect initial cookies
-    info!("Popular: step 1 - visiting homepage for cookies");
-    let homepage = browser_headers(popular_client.get("https://popularenlinea.com/personas/Paginas/Home.aspx"))
+    info!("Popular: session cookies stale or absent, solving Incapsula challenge");
+    solve_incapsula_challenge(session).await;
+
+    match fetch_popular_api(session).await {
+        ApiAttempt::Answered(outcome) => outcome,
+        ApiAttempt::NeedsChallenge => {
+            error!("Popular: API still unreachable after solving the Incapsula challenge");
+            FetchOutcome::Retryable
+        }
+    }
+}
+
+/// Calls the rates API with whatever cookies `session` currently holds.
+/// A non-success status or a challenge-shaped body means the cookies are
+/// stale rather than that the fetch failed outright.
+async fn fetch_popular_api(session: &Client) -> ApiAttempt {
+    let response = match browser_headers(session.get(POPULAR_API_URL))
+        .header("Accept", "application/xml")
         .send()
         .await
-        .map_err(|e| error!("Popular homepage request failed: {e}"))
-        .ok()?;
+    {
+        Ok(response) => response,
+        Err(e) => {
+            error!("Popular API request failed: {e}");
+            return ApiAttempt::Answered(FetchOutcome::Retryable);
+        }
+    };
+
+    if !response.status().is_success() {
+        warn!(
+            "Popular API returned HTTP {}, session cookies look stale",
+            response.status()
+        );
+        return ApiAttempt::NeedsChallenge;
+    }
+
+    let xml = match response.text().await {
+        Ok(xml) => xml,
+        Err(e) => {
+            error!("Popular body read failed: {e}");
+            return ApiAttempt::Answered(FetchOutcome::Retryable);
+        }
+    };
+
+    if looks_like_waf_challenge(&xml) {
+        warn!("Popular API returned a challenge page, session cookies look stale");
+        return ApiAttempt::NeedsChallenge;
+    }
+
+    ApiAttempt::Answered(parse_popular_xml(&xml))
+}
+
+/// Runs the homepage -> challenge script -> homepage retry dance against
+/// `session`, leaving solved Incapsula cookies in its cookie jar for
+/// `fetch_popular_api` (this call and future ones) to reuse.
+async fn solve_incapsula_challenge(session: &Client) {
+    info!("Popular: step 1 - visiting homepage for cookies");
+    let homepage =
+        match browser_headers(session.get("https://popularenlinea.com/personas/Paginas/Home.aspx"))
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                error!("Popular homepage request failed: {e}");
+                return;
+            }
+        };
 
     let body = homepage.text().await.unwrap_or_default();
 
-    // Step 2: Extract and fetch the Incapsula challenge script (sets session cookies)
-    let script_re = regex::Regex::new(r#"src="(/_Incapsula_Resource\?SWJIYLWA=[^"]+)""#).ok()?;
+    // Extract and fetch the Incapsula challenge script (sets session cookies)
+    let script_re = regex::Regex::new(r#"src="(/_Incapsula_Resource\?SWJIYLWA=[^"]+)""#).unwrap();
     if let Some(caps) = script_re.captures(&body) {
         let script_url = format!("https://popularenlinea.com{}", &caps[1]);
         info!("Popular: step 2 - fetching Incapsula challenge script");
-        let _ = browser_headers(popular_client.get(&script_url))
-            .header("Referer", "https://popularenlinea.com/personas/Paginas/Home.aspx")
+        let _ = browser_headers(session.get(&script_url))
+            .header(
+                "Referer",
+                "https://popularenlinea.com/personas/Paginas/Home.aspx",
+            )
             .send()
             .await;
     }
 
     // Small delay to mimic browser behavior
-    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    tokio::time::sleep(Duration::from_millis(500)).await;
 
-    // Step 3: Retry the homepage with accumulated cookies
     info!("Popular: step 3 - retrying homepage with cookies");
-    let _ = browser_headers(popular_client.get("https://popularenlinea.com/personas/Paginas/Home.aspx"))
+    let _ = browser_headers(session.get("https://popularenlinea.com/personas/Paginas/Home.aspx"))
         .send()
         .await;
 
-    tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+}
 
-    // Step 4: Now fetch the actual rates API with the session cookies
-    info!("Popular: step 4 - fetching rates API");
-    let api_url = "https://popularenlinea.com/_api/web/lists/getbytitle('Rates')/items?$filter=ItemID%20eq%20%271%27";
-    let response = browser_headers(popular_client.get(api_url))
-        .header("Accept", "application/xml")
-        .send()
-        .await
-        .map_err(|e| error!("Popular API request failed: {e}"))
-        .ok()?;
+pub struct PopularFetcher {
+    retry: RetryPolicy,
+    // Persists across fetch cycles so Incapsula session cookies survive
+    // between background runs instead of being solved from scratch every
+    // 30 minutes.
+    session: Client,
+}
 
-    if !response.status().is_success() {
-        error!("Popular API returned HTTP {}", response.status());
-        return None;
+impl PopularFetcher {
+    pub fn new() -> Self {
+        let jar = Arc::new(reqwest::cookie::Jar::default());
+        let session = reqwest::Client::builder()
+            .cookie_provider(jar)
+            .redirect(reqwest::redirect::Policy::limited(10))
+            .build()
+            .expect("failed to build Popular session client");
+
+        Self {
+            retry: RetryPolicy::from_env("POPULAR"),
+            session,
+        }
     }
+}
 
-    let xml = response
-        .text()
+impl Default for PopularFetcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait::async_trait]
+impl BankFetcher for PopularFetcher {
+    fn bank_class(&self) -> &'static str {
+        "popular"
+    }
+
+    async fn fetch(&self, client: &Client) -> Option<FetchedRate> {
+        retry_fetch("Popular", self.retry, || {
+            popular_attempt(client, &self.session)
+        })
         .await
-        .map_err(|e| error!("Popular body read failed: {e}"))
-        .ok()?;
+    }
+}
 
-    parse_popular_xml(&xml)
+/// Split out from `popular_attempt` so a saved fixture can be run through
+/// the same regexes offline (see the `parse-file` CLI subcommand).
+pub(crate) fn parse_popular_xml(xml: &str) -> FetchOutcome {
+    let buy_re = regex::Regex::new(r"<d:DollarBuyRate[^>]*>(\d+\.?\d*)</d:DollarBuyRate>").unwrap();
+    let sell_re =
+        regex::Regex::new(r"<d:DollarSellRate[^>]*>(\d+\.?\d*)</d:DollarSellRate>").unwrap();
+
+    let Some(buy_caps) = buy_re.captures(xml) else {
+        if looks_like_waf_challenge(xml) {
+            warn!("Popular: response looks like a WAF challenge, will retry");
+            return FetchOutcome::Retryable;
+        }
+        error!("Popular: DollarBuyRate not found in XML");
+        return FetchOutcome::Fatal;
+    };
+    let Some(sell_caps) = sell_re.captures(xml) else {
+        error!("Popular: DollarSellRate not found in XML");
+        return FetchOutcome::Fatal;
+    };
+
+    let (Ok(dollar_buy_rate), Ok(dollar_sell_rate)) = (buy_caps[1].parse(), sell_caps[1].parse())
+    else {
+        error!("Popular: matched rates were not valid numbers");
+        return FetchOutcome::Fatal;
+    };
+
+    info!("Popular: buy={dollar_buy_rate:.2} sell={dollar_sell_rate:.2}");
+
+    FetchOutcome::Success(FetchedRate {
+        bank_name: "Banco Popular".into(),
+        bank_class: "popular".into(),
+        dollar_buy_rate,
+        dollar_sell_rate,
+    })
 }
 
-fn parse_popular_xml(xml: &str) -> Option<FetchedRate> {
-    let buy_re = regex::Regex::new(r"<d:DollarBuyRate[^>]*>(\d+\.?\d*)</d:DollarBuyRate>").ok()?;
-    let sell_re = regex::Regex::new(r"<d:DollarSellRate[^>]*>(\d+\.?\d*)</d:DollarSellRate>").ok()?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let buy_rate: f64 = buy_re
-        .captures(xml)
-        .or_else(|| { error!("Popular: DollarBuyRate not found in XML"); None })?[1]
-        .parse()
-        .ok()?;
+    fn unwrap_success(outcome: FetchOutcome) -> FetchedRate {
+        match outcome {
+            FetchOutcome::Success(rate) => rate,
+            FetchOutcome::Retryable => panic!("expected Success, got Retryable"),
+            FetchOutcome::Fatal => panic!("expected Success, got Fatal"),
+        }
+    }
 
-    let sell_rate: f64 = sell_re
-        .captures(xml)
-        .or_else(|| { error!("Popular: DollarSellRate not found in XML"); None })?[1]
-        .parse()
-        .ok()?;
+    #[test]
+    fn parse_banreservas_html_extracts_rates() {
+        let html = "<html><body><div>Compra 58.50 algo Venta 59.20</div></body></html>";
+        let rate = unwrap_success(parse_banreservas_html(html));
+        assert_eq!(rate.bank_class, "banreservas");
+        assert_eq!(rate.dollar_buy_rate, 58.50);
+        assert_eq!(rate.dollar_sell_rate, 59.20);
+    }
 
-    info!("Popular: buy={buy_rate:.2} sell={sell_rate:.2}");
+    #[test]
+    fn parse_banreservas_html_short_body_is_retryable() {
+        // Short bodies are treated as a likely WAF interstitial, not real content.
+        assert!(matches!(
+            parse_banreservas_html("redirecting..."),
+            FetchOutcome::Retryable
+        ));
+    }
 
-    Some(FetchedRate {
-        bank_name: "Banco Popular".into(),
-        bank_class: "popular".into(),
-        dollar_buy_rate: buy_rate,
-        dollar_sell_rate: sell_rate,
-    })
+    #[test]
+    fn parse_banreservas_html_unrecognized_content_is_fatal() {
+        let html = "<html><body>".to_string()
+            + &"Banreservas updated its calculator page layout and rates are now presented differently than before so the old regex no longer finds Compra or Venta anywhere in this much longer filler paragraph."
+            + "</body></html>";
+        assert!(matches!(parse_banreservas_html(&html), FetchOutcome::Fatal));
+    }
+
+    #[test]
+    fn parse_bhd_json_extracts_usd_rate() {
+        let body = r#"{
+            "data": {
+                "attributes": {
+                    "exchangeRates": [
+                        {"currency": "EUR", "buyingRate": 63.0, "sellingRate": 64.0},
+                        {"currency": "USD", "buyingRate": 58.1, "sellingRate": 58.9}
+                    ]
+                }
+            }
+        }"#;
+        let rate = unwrap_success(parse_bhd_json(body));
+        assert_eq!(rate.bank_class, "bhd");
+        assert_eq!(rate.dollar_buy_rate, 58.1);
+        assert_eq!(rate.dollar_sell_rate, 58.9);
+    }
+
+    #[test]
+    fn parse_bhd_json_waf_challenge_body_is_retryable() {
+        assert!(matches!(
+            parse_bhd_json("please wait"),
+            FetchOutcome::Retryable
+        ));
+    }
+
+    #[test]
+    fn parse_bhd_json_unexpected_shape_is_fatal() {
+        let body = r#"{"data": {"attributes": {"exchangeRates": [{"currency": "EUR", "buyingRate": 63.0, "sellingRate": 64.0}]}}}"#;
+        assert!(matches!(parse_bhd_json(body), FetchOutcome::Fatal));
+    }
+
+    #[test]
+    fn parse_popular_xml_extracts_rates() {
+        let xml = "<entry><d:DollarBuyRate m:type=\"Edm.Double\">58.75</d:DollarBuyRate><d:DollarSellRate m:type=\"Edm.Double\">59.35</d:DollarSellRate></entry>";
+        let rate = unwrap_success(parse_popular_xml(xml));
+        assert_eq!(rate.bank_class, "popular");
+        assert_eq!(rate.dollar_buy_rate, 58.75);
+        assert_eq!(rate.dollar_sell_rate, 59.35);
+    }
+
+    #[test]
+    fn parse_popular_xml_waf_challenge_body_is_retryable() {
+        assert!(matches!(
+            parse_popular_xml("short"),
+            FetchOutcome::Retryable
+        ));
+    }
+
+    #[test]
+    fn retry_policy_delay_never_exceeds_max_delay() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(300),
+            max_delay: Duration::from_secs(30),
+        };
+
+        for attempt in 0..10 {
+            assert!(policy.delay_for_attempt(attempt) <= policy.max_delay);
+        }
+    }
 }
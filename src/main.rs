@@ -1,13 +1,100 @@
 mod fetchers;
 
 use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use cron::Schedule;
+use futures::stream::{self, StreamExt};
 use reqwest::Client;
 use rocket::serde::json::Json;
 use rocket::{get, routes, State};
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
+use std::env;
+use std::str::FromStr;
+use std::sync::Arc;
 use tokio::time::{interval, Duration};
-use tracing::{error, info};
+use tracing::{error, info, warn};
+
+// --- CLI ---
+
+/// In addition to launching the Rocket server (the default with no
+/// subcommand), this binary doubles as a debugging CLI so a maintainer
+/// can iterate on a bank's scraper without hitting the live, WAF-protected
+/// site on every attempt.
+#[derive(Parser)]
+#[command(name = "dollar-rates")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run exactly one bank's fetcher and print the parsed rate (or error) to stdout
+    ScrapBank { bank_class: String },
+    /// Feed a saved fixture through a bank's parser without any network call
+    ParseFile { bank_class: String, path: String },
+    /// Run update_all_rates once against the DB and exit
+    UpdateOnce,
+}
+
+async fn run_cli_command(command: Command, pool: &PgPool) {
+    match command {
+        Command::ScrapBank { bank_class } => {
+            let client = Client::new();
+            match fetchers::registry()
+                .into_iter()
+                .find(|f| f.bank_class() == bank_class)
+            {
+                Some(fetcher) => match fetcher.fetch(&client).await {
+                    Some(rate) => print_rate(&rate),
+                    None => println!("{bank_class}: fetch failed (see logs above)"),
+                },
+                None => eprintln!("Unknown bank_class '{bank_class}'"),
+            }
+        }
+        Command::ParseFile { bank_class, path } => {
+            let body = match std::fs::read_to_string(&path) {
+                Ok(body) => body,
+                Err(e) => {
+                    eprintln!("Failed to read {path}: {e}");
+                    return;
+                }
+            };
+            let outcome = match bank_class.as_str() {
+                "banreservas" => fetchers::parse_banreservas_html(&body),
+                "bhd" => fetchers::parse_bhd_json(&body),
+                "popular" => fetchers::parse_popular_xml(&body),
+                other => {
+                    eprintln!("Unknown bank_class '{other}'");
+                    return;
+                }
+            };
+            match outcome {
+                fetchers::FetchOutcome::Success(rate) => print_rate(&rate),
+                fetchers::FetchOutcome::Retryable => {
+                    eprintln!("parse failed: fixture looks like a WAF challenge page")
+                }
+                fetchers::FetchOutcome::Fatal => {
+                    eprintln!("parse failed: fixture did not match the expected shape")
+                }
+            }
+        }
+        Command::UpdateOnce => update_all_rates(pool, &fetchers::registry()).await,
+    }
+}
+
+fn print_rate(rate: &fetchers::FetchedRate) {
+    println!(
+        "{}",
+        serde_json::json!({
+            "bank_name": rate.bank_name,
+            "bank_class": rate.bank_class,
+            "dollar_buy_rate": rate.dollar_buy_rate,
+            "dollar_sell_rate": rate.dollar_sell_rate,
+        })
+    );
+}
 
 // --- Models ---
 
@@ -22,6 +109,81 @@ pub struct BankRate {
     pub created_at: Option<DateTime<Utc>>,
 }
 
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+struct RawHistoryPoint {
+    bank_class: String,
+    dollar_buy_rate: f64,
+    dollar_sell_rate: f64,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+struct BucketedHistoryPoint {
+    bank_class: String,
+    bucket: DateTime<Utc>,
+    min_buy_rate: f64,
+    max_buy_rate: f64,
+    avg_buy_rate: f64,
+    last_buy_rate: f64,
+    min_sell_rate: f64,
+    max_sell_rate: f64,
+    avg_sell_rate: f64,
+    last_sell_rate: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HistoryBucket {
+    Raw,
+    Hourly,
+    Daily,
+}
+
+impl HistoryBucket {
+    fn parse(raw: Option<&str>) -> Option<Self> {
+        match raw.unwrap_or("raw") {
+            "raw" => Some(Self::Raw),
+            "hourly" => Some(Self::Hourly),
+            "daily" => Some(Self::Daily),
+            _ => None,
+        }
+    }
+
+    fn trunc_field(&self) -> &'static str {
+        match self {
+            Self::Raw => "",
+            Self::Hourly => "hour",
+            Self::Daily => "day",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct BankRateComparison {
+    bank_name: String,
+    bank_class: String,
+    dollar_buy_rate: f64,
+    dollar_sell_rate: f64,
+    spread: f64,
+    updated_at: Option<DateTime<Utc>>,
+    stale: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct MarketAverage {
+    dollar_buy_rate: f64,
+    dollar_sell_rate: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct BestRates {
+    // Highest dollar_buy_rate — best for someone selling USD.
+    best_buy: BankRateComparison,
+    // Lowest dollar_sell_rate — best for someone buying USD.
+    best_sell: BankRateComparison,
+    market_average: MarketAverage,
+    banks: Vec<BankRateComparison>,
+}
+
 // --- Routes ---
 
 #[get("/")]
@@ -71,6 +233,148 @@ async fn get_rate_by_bank(pool: &State<PgPool>, bank_class: &str) -> Json<serde_
     }
 }
 
+#[get("/rates/best")]
+async fn get_best_rates(pool: &State<PgPool>) -> Json<serde_json::Value> {
+    match sqlx::query_as::<_, BankRate>("SELECT * FROM bank_rates ORDER BY bank_class")
+        .fetch_all(pool.inner())
+        .await
+    {
+        Ok(rates) if rates.is_empty() => Json(serde_json::json!({
+            "success": false,
+            "error": "no bank rates available yet",
+        })),
+        Ok(rates) => Json(serde_json::json!({
+            "success": true,
+            "data": best_rates(&rates),
+        })),
+        Err(e) => Json(serde_json::json!({
+            "success": false,
+            "error": e.to_string(),
+        })),
+    }
+}
+
+/// Minutes since `updated_at` past which a bank's rate is flagged `stale`,
+/// tuned via `STALE_AFTER_MINUTES` (default 60 — twice the default update
+/// cycle, so one missed scrape doesn't immediately flag a bank).
+fn stale_after_minutes() -> i64 {
+    env::var("STALE_AFTER_MINUTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(60)
+}
+
+fn best_rates(rates: &[BankRate]) -> BestRates {
+    let threshold = stale_after_minutes();
+    let now = Utc::now();
+
+    let banks: Vec<BankRateComparison> = rates
+        .iter()
+        .map(|rate| BankRateComparison {
+            bank_name: rate.bank_name.clone(),
+            bank_class: rate.bank_class.clone(),
+            dollar_buy_rate: rate.dollar_buy_rate,
+            dollar_sell_rate: rate.dollar_sell_rate,
+            spread: rate.dollar_sell_rate - rate.dollar_buy_rate,
+            updated_at: rate.updated_at,
+            stale: rate
+                .updated_at
+                .map(|updated_at| (now - updated_at).num_minutes() > threshold)
+                .unwrap_or(true),
+        })
+        .collect();
+
+    // Safe to unwrap: callers only reach here with a non-empty `rates`.
+    let best_buy = banks
+        .iter()
+        .max_by(|a, b| a.dollar_buy_rate.total_cmp(&b.dollar_buy_rate))
+        .cloned()
+        .unwrap();
+    let best_sell = banks
+        .iter()
+        .min_by(|a, b| a.dollar_sell_rate.total_cmp(&b.dollar_sell_rate))
+        .cloned()
+        .unwrap();
+
+    let count = banks.len() as f64;
+    let market_average = MarketAverage {
+        dollar_buy_rate: banks.iter().map(|b| b.dollar_buy_rate).sum::<f64>() / count,
+        dollar_sell_rate: banks.iter().map(|b| b.dollar_sell_rate).sum::<f64>() / count,
+    };
+
+    BestRates {
+        best_buy,
+        best_sell,
+        market_average,
+        banks,
+    }
+}
+
+#[get("/rates/history?<from>&<to>&<interval>")]
+async fn get_all_rates_history(
+    pool: &State<PgPool>,
+    from: Option<&str>,
+    to: Option<&str>,
+    interval: Option<&str>,
+) -> Json<serde_json::Value> {
+    rate_history(pool, None, from, to, interval).await
+}
+
+#[get("/rates/<bank_class>/history?<from>&<to>&<interval>")]
+async fn get_rate_history(
+    pool: &State<PgPool>,
+    bank_class: &str,
+    from: Option<&str>,
+    to: Option<&str>,
+    interval: Option<&str>,
+) -> Json<serde_json::Value> {
+    rate_history(pool, Some(bank_class), from, to, interval).await
+}
+
+async fn rate_history(
+    pool: &State<PgPool>,
+    bank_class: Option<&str>,
+    from: Option<&str>,
+    to: Option<&str>,
+    interval: Option<&str>,
+) -> Json<serde_json::Value> {
+    let Some(bucket) = HistoryBucket::parse(interval) else {
+        return Json(serde_json::json!({
+            "success": false,
+            "error": "invalid interval, expected one of: raw, hourly, daily",
+        }));
+    };
+
+    let from = match parse_history_bound("from", from) {
+        Ok(bound) => bound,
+        Err(e) => return Json(serde_json::json!({ "success": false, "error": e })),
+    };
+    let to = match parse_history_bound("to", to) {
+        Ok(bound) => bound,
+        Err(e) => return Json(serde_json::json!({ "success": false, "error": e })),
+    };
+
+    match bucket {
+        HistoryBucket::Raw => match fetch_raw_history(pool.inner(), bank_class, from, to).await {
+            Ok(points) => Json(serde_json::json!({ "success": true, "data": points })),
+            Err(e) => Json(serde_json::json!({ "success": false, "error": e.to_string() })),
+        },
+        _ => match fetch_bucketed_history(pool.inner(), bank_class, from, to, bucket).await {
+            Ok(points) => Json(serde_json::json!({ "success": true, "data": points })),
+            Err(e) => Json(serde_json::json!({ "success": false, "error": e.to_string() })),
+        },
+    }
+}
+
+fn parse_history_bound(name: &str, raw: Option<&str>) -> Result<Option<DateTime<Utc>>, String> {
+    match raw {
+        None => Ok(None),
+        Some(s) => DateTime::parse_from_rfc3339(s)
+            .map(|dt| Some(dt.with_timezone(&Utc)))
+            .map_err(|_| format!("'{name}' must be an ISO-8601 timestamp")),
+    }
+}
+
 // --- Database operations ---
 
 async fn upsert_rate(pool: &PgPool, rate: &fetchers::FetchedRate) {
@@ -114,28 +418,156 @@ async fn upsert_rate(pool: &PgPool, rate: &fetchers::FetchedRate) {
     }
 }
 
-async fn update_all_rates(pool: &PgPool) {
+async fn fetch_raw_history(
+    pool: &PgPool,
+    bank_class: Option<&str>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+) -> Result<Vec<RawHistoryPoint>, sqlx::Error> {
+    sqlx::query_as::<_, RawHistoryPoint>(
+        r#"
+        SELECT bank_class, dollar_buy_rate, dollar_sell_rate, created_at
+        FROM bank_rates_log
+        WHERE ($1::text IS NULL OR bank_class = $1)
+          AND ($2::timestamptz IS NULL OR created_at >= $2)
+          AND ($3::timestamptz IS NULL OR created_at <= $3)
+        ORDER BY bank_class, created_at
+        "#,
+    )
+    .bind(bank_class)
+    .bind(from)
+    .bind(to)
+    .fetch_all(pool)
+    .await
+}
+
+async fn fetch_bucketed_history(
+    pool: &PgPool,
+    bank_class: Option<&str>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    bucket: HistoryBucket,
+) -> Result<Vec<BucketedHistoryPoint>, sqlx::Error> {
+    sqlx::query_as::<_, BucketedHistoryPoint>(
+        r#"
+        SELECT
+            bank_class,
+            date_trunc($4, created_at) AS bucket,
+            MIN(dollar_buy_rate) AS min_buy_rate,
+            MAX(dollar_buy_rate) AS max_buy_rate,
+            AVG(dollar_buy_rate) AS avg_buy_rate,
+            (array_agg(dollar_buy_rate ORDER BY created_at DESC))[1] AS last_buy_rate,
+            MIN(dollar_sell_rate) AS min_sell_rate,
+            MAX(dollar_sell_rate) AS max_sell_rate,
+            AVG(dollar_sell_rate) AS avg_sell_rate,
+            (array_agg(dollar_sell_rate ORDER BY created_at DESC))[1] AS last_sell_rate
+        FROM bank_rates_log
+        WHERE ($1::text IS NULL OR bank_class = $1)
+          AND ($2::timestamptz IS NULL OR created_at >= $2)
+          AND ($3::timestamptz IS NULL OR created_at <= $3)
+        GROUP BY bank_class, bucket
+        ORDER BY bank_class, bucket
+        "#,
+    )
+    .bind(bank_class)
+    .bind(from)
+    .bind(to)
+    .bind(bucket.trunc_field())
+    .fetch_all(pool)
+    .await
+}
+
+/// How many bank fetchers may be in flight at once. Bounded rather than
+/// fully parallel so a growing fetcher registry doesn't hammer every bank
+/// (and every WAF) at the exact same instant.
+const FETCH_CONCURRENCY: usize = 4;
+
+async fn update_all_rates(pool: &PgPool, fetchers: &[Arc<dyn fetchers::BankFetcher>]) {
     let client = Client::new();
 
-    let (banreservas, bhd, popular) = tokio::join!(
-        fetchers::fetch_banreservas(&client),
-        fetchers::fetch_bhd(&client),
-        fetchers::fetch_popular(&client),
-    );
+    // Each future below owns a clone of `fetcher` and `client` (both cheap:
+    // an `Arc` bump and a `reqwest::Client` is itself `Arc`-backed) rather
+    // than borrowing them, so the stream has no lifetime tied to this stack
+    // frame — required since `update_all_rates` is driven from inside a
+    // `tokio::spawn`'d background task, which needs `'static` futures.
+    let futures = fetchers.iter().map(|fetcher| {
+        let fetcher = fetcher.clone();
+        let client = client.clone();
+        async move { fetcher.fetch(&client).await }
+    });
 
-    for rate in [banreservas, bhd, popular].into_iter().flatten() {
+    let rates: Vec<Option<fetchers::FetchedRate>> = stream::iter(futures)
+        .buffer_unordered(FETCH_CONCURRENCY)
+        .collect()
+        .await;
+
+    for rate in rates.into_iter().flatten() {
         upsert_rate(pool, &rate).await;
     }
 }
 
 // --- Background task ---
 
-async fn rate_updater(pool: PgPool, interval_minutes: u64) {
+/// Drives `update_all_rates` on a schedule. Prefers a cron expression from
+/// `RATE_CRON` (e.g. `"0 */15 9-17 * * MON-FRI"`) so operators can poll
+/// often during banking hours and back off overnight/weekends; falls back
+/// to the fixed `interval_minutes` loop if the env var is absent or fails
+/// to parse.
+///
+/// `fetchers` is built once at startup and reused for every cycle so that
+/// fetchers with their own persistent state (e.g. Popular's Incapsula
+/// session cookies) keep that state across runs instead of starting cold.
+async fn rate_updater(
+    pool: PgPool,
+    interval_minutes: u64,
+    fetchers: Arc<Vec<Arc<dyn fetchers::BankFetcher>>>,
+) {
+    match env::var("RATE_CRON") {
+        Ok(expr) => match Schedule::from_str(&expr) {
+            Ok(schedule) => {
+                info!("Using RATE_CRON schedule: {expr}");
+                cron_loop(pool, schedule, fetchers).await;
+            }
+            Err(e) => {
+                warn!("RATE_CRON=\"{expr}\" failed to parse ({e}), falling back to fixed interval");
+                fixed_interval_loop(pool, interval_minutes, fetchers).await;
+            }
+        },
+        Err(_) => fixed_interval_loop(pool, interval_minutes, fetchers).await,
+    }
+}
+
+async fn fixed_interval_loop(
+    pool: PgPool,
+    interval_minutes: u64,
+    fetchers: Arc<Vec<Arc<dyn fetchers::BankFetcher>>>,
+) {
     let mut ticker = interval(Duration::from_secs(interval_minutes * 60));
     loop {
         ticker.tick().await;
         info!("Updating bank rates...");
-        update_all_rates(&pool).await;
+        update_all_rates(&pool, &fetchers).await;
+        info!("Bank rates update complete.");
+    }
+}
+
+async fn cron_loop(
+    pool: PgPool,
+    schedule: Schedule,
+    fetchers: Arc<Vec<Arc<dyn fetchers::BankFetcher>>>,
+) {
+    loop {
+        let Some(next) = schedule.upcoming(Utc).next() else {
+            error!("Cron schedule has no upcoming fire time, stopping scheduler");
+            return;
+        };
+        let wait = (next - Utc::now())
+            .to_std()
+            .unwrap_or(Duration::from_secs(0));
+        info!("Next rate update scheduled for {next}");
+        tokio::time::sleep(wait).await;
+        info!("Updating bank rates...");
+        update_all_rates(&pool, &fetchers).await;
         info!("Bank rates update complete.");
     }
 }
@@ -143,26 +575,146 @@ async fn rate_updater(pool: PgPool, interval_minutes: u64) {
 // --- Entry point ---
 
 #[shuttle_runtime::main]
-async fn rocket(
-    #[shuttle_shared_db::Postgres] pool: PgPool,
-) -> shuttle_rocket::ShuttleRocket {
+async fn rocket(#[shuttle_shared_db::Postgres] pool: PgPool) -> shuttle_rocket::ShuttleRocket {
     // Run migrations
     sqlx::migrate!()
         .run(&pool)
         .await
         .expect("Failed to run migrations");
 
+    // One-shot debugging subcommands short-circuit before the server starts.
+    // This function is the deployed server's actual entrypoint, so a bad or
+    // unrecognized argv (e.g. extra flags from the process supervisor) must
+    // fall through to starting Rocket rather than hard-exiting via `parse()`.
+    match Cli::try_parse() {
+        Ok(Cli {
+            command: Some(command),
+        }) => {
+            run_cli_command(command, &pool).await;
+            std::process::exit(0);
+        }
+        Ok(Cli { command: None }) => {}
+        Err(e) => {
+            // clap formats `--help`/`--version` output and usage errors into
+            // `e` itself; print it so a typo'd subcommand or `--help` shows
+            // something instead of silently booting the full server.
+            eprintln!("{e}");
+        }
+    }
+
+    // Built once and reused for every cycle so fetchers with persistent
+    // state (e.g. Popular's Incapsula session cookies) keep it across runs.
+    let fetchers = Arc::new(fetchers::registry());
+
     // Initial rate fetch
     info!("Fetching initial bank rates...");
-    update_all_rates(&pool).await;
+    update_all_rates(&pool, &fetchers).await;
 
     // Spawn background updater (every 30 minutes)
     let updater_pool = pool.clone();
-    tokio::spawn(rate_updater(updater_pool, 30));
+    // 30 minutes is the fallback; a valid RATE_CRON schedule takes precedence.
+    tokio::spawn(rate_updater(updater_pool, 30, fetchers));
 
-    let rocket = rocket::build()
-        .manage(pool)
-        .mount("/", routes![health, get_rates, get_rate_by_bank]);
+    let rocket = rocket::build().manage(pool).mount(
+        "/",
+        routes![
+            health,
+            get_rates,
+            get_rate_by_bank,
+            get_best_rates,
+            get_rate_history,
+            get_all_rates_history,
+        ],
+    );
 
     Ok(rocket.into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_rate(
+        bank_class: &str,
+        buy: f64,
+        sell: f64,
+        updated_at: Option<DateTime<Utc>>,
+    ) -> BankRate {
+        BankRate {
+            id: 1,
+            bank_name: bank_class.to_string(),
+            bank_class: bank_class.to_string(),
+            dollar_buy_rate: buy,
+            dollar_sell_rate: sell,
+            updated_at,
+            created_at: updated_at,
+        }
+    }
+
+    #[test]
+    fn history_bucket_parses_known_values() {
+        assert_eq!(HistoryBucket::parse(None), Some(HistoryBucket::Raw));
+        assert_eq!(HistoryBucket::parse(Some("raw")), Some(HistoryBucket::Raw));
+        assert_eq!(
+            HistoryBucket::parse(Some("hourly")),
+            Some(HistoryBucket::Hourly)
+        );
+        assert_eq!(
+            HistoryBucket::parse(Some("daily")),
+            Some(HistoryBucket::Daily)
+        );
+    }
+
+    #[test]
+    fn history_bucket_rejects_unknown_values() {
+        assert_eq!(HistoryBucket::parse(Some("weekly")), None);
+    }
+
+    #[test]
+    fn best_rates_picks_highest_buy_and_lowest_sell() {
+        let now = Utc::now();
+        let rates = vec![
+            sample_rate("banreservas", 58.00, 59.00, Some(now)),
+            sample_rate("bhd", 58.50, 58.90, Some(now)),
+            sample_rate("popular", 58.10, 59.10, Some(now)),
+        ];
+
+        let comparison = best_rates(&rates);
+
+        assert_eq!(comparison.best_buy.bank_class, "bhd");
+        assert_eq!(comparison.best_sell.bank_class, "bhd");
+        assert!((comparison.market_average.dollar_buy_rate - 58.2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn best_rates_flags_stale_banks() {
+        let now = Utc::now();
+        let rates = vec![
+            sample_rate("banreservas", 58.00, 59.00, Some(now)),
+            sample_rate("bhd", 58.50, 58.90, Some(now - chrono::Duration::hours(3))),
+            sample_rate("popular", 58.10, 59.10, None),
+        ];
+
+        let comparison = best_rates(&rates);
+
+        let banreservas = comparison
+            .banks
+            .iter()
+            .find(|b| b.bank_class == "banreservas")
+            .unwrap();
+        let bhd = comparison
+            .banks
+            .iter()
+            .find(|b| b.bank_class == "bhd")
+            .unwrap();
+        let popular = comparison
+            .banks
+            .iter()
+            .find(|b| b.bank_class == "popular")
+            .unwrap();
+
+        assert!(!banreservas.stale);
+        assert!(bhd.stale);
+        assert!(popular.stale);
+    }
+}